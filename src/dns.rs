@@ -0,0 +1,614 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    net::{IpAddr, ToSocketAddrs, UdpSocket},
+    ops::Range,
+    process,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, anyhow};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use lettre::address::Envelope;
+use openssl::pkey::{Id, PKey};
+
+use crate::{
+    Args, DkimAlgorithm,
+    json::json_string,
+    log::{emit_log, log_verbose, log_warning},
+    transport::{Connection, Endpoint},
+};
+
+/// Compares the public key published at `<selector>._domainkey.<domain>` with the
+/// one derived from `--dkim-key`.
+pub fn check_dkim_dns(args: &Args) -> Result<()> {
+    let (Some(selector), Some(domain), Some(path)) =
+        (&args.dkim_selector, &args.dkim_domain, &args.dkim_key)
+    else {
+        return Err(anyhow!(
+            "--check-dkim-dns requires --dkim-selector, --dkim-domain, and --dkim-key"
+        ));
+    };
+    let key = fs::read_to_string(path)
+        .with_context(|| format!("failed to read DKIM key {}", path.display()))?;
+    let expected = dkim_public_key(args.dkim_algorithm, &key)?;
+
+    let name = format!("{selector}._domainkey.{domain}");
+    log_verbose(args.verbose, &format!("Looking up TXT records for {name}"));
+    let records = lookup_txt(&name)?;
+    let record = records
+        .iter()
+        .find(|record| record.split(';').any(|tag| tag.trim().starts_with("p=")))
+        .ok_or_else(|| anyhow!("no DKIM TXT record published at {name}"))?;
+    let tags = record
+        .split(';')
+        .filter_map(|tag| tag.split_once('='))
+        .map(|(tag, value)| (tag.trim(), value.trim()))
+        .collect::<HashMap<_, _>>();
+
+    let key_type = tags.get("k").copied().unwrap_or("rsa");
+    if !key_type.eq_ignore_ascii_case(args.dkim_algorithm.key_type()) {
+        return Err(anyhow!(
+            "DKIM record at {name} is for k={key_type}, but --dkim-algorithm is {}",
+            args.dkim_algorithm.key_type()
+        ));
+    }
+    let published = tags.get("p").copied().unwrap_or_default();
+    if published.is_empty() {
+        return Err(anyhow!(
+            "DKIM record at {name} has an empty p= tag (key revoked)"
+        ));
+    }
+    let published = BASE64_STANDARD
+        .decode(published.split_whitespace().collect::<String>())
+        .with_context(|| format!("DKIM record at {name} has an invalid p= tag"))?;
+
+    if published != expected {
+        return Err(anyhow!(
+            "DKIM key published at {name} does not match {}",
+            path.display()
+        ));
+    }
+    println!("DKIM key published at {name} matches {}", path.display());
+    Ok(())
+}
+
+/// Public key bytes as they appear, base64-decoded, in a DKIM `p=` tag.
+fn dkim_public_key(algorithm: DkimAlgorithm, key: &str) -> Result<Vec<u8>> {
+    match algorithm {
+        DkimAlgorithm::Rsa => PKey::private_key_from_pem(key.as_bytes())
+            .context("failed to parse DKIM signing key")?
+            .public_key_to_der()
+            .context("failed to derive DKIM public key"),
+        DkimAlgorithm::Ed25519 => {
+            let seed = BASE64_STANDARD
+                .decode(key.trim())
+                .context("failed to parse DKIM signing key")?;
+            PKey::private_key_from_raw_bytes(&seed, Id::ED25519)
+                .context("failed to parse DKIM signing key")?
+                .raw_public_key()
+                .context("failed to derive DKIM public key")
+        }
+    }
+}
+
+/// Outcome of an SPF evaluation, named as in RFC 7208.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SpfResult {
+    Pass,
+    Fail,
+    SoftFail,
+    Neutral,
+    None,
+}
+
+impl SpfResult {
+    fn from_qualifier(qualifier: char) -> Option<Self> {
+        match qualifier {
+            '+' => Some(SpfResult::Pass),
+            '-' => Some(SpfResult::Fail),
+            '~' => Some(SpfResult::SoftFail),
+            '?' => Some(SpfResult::Neutral),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SpfResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SpfResult::Pass => "pass",
+            SpfResult::Fail => "fail",
+            SpfResult::SoftFail => "softfail",
+            SpfResult::Neutral => "neutral",
+            SpfResult::None => "none",
+        })
+    }
+}
+
+/// RFC 7208 caps the DNS-querying terms evaluated for one check.
+const SPF_LOOKUP_LIMIT: usize = 10;
+
+/// Evaluates the SPF record of the envelope sender's domain against the
+/// outbound address. Anything but a pass, including a lookup error, is a
+/// warning, so the send only stops under `--strict`.
+pub(crate) fn check_spf(args: &Args, conn: &Connection, envelope: &Envelope) -> Result<()> {
+    let Some(sender) = envelope.from() else {
+        return log_warning("skipping SPF check: the envelope has no sender");
+    };
+    let domain = sender.domain();
+    let ip = match args.spf_ip {
+        Some(ip) => ip,
+        None => match outbound_ip(conn) {
+            Ok(ip) => ip,
+            Err(error) => return log_warning(&format!("skipping SPF check: {error:#}")),
+        },
+    };
+    log_verbose(
+        args.verbose,
+        &format!("Checking SPF for {domain} from {ip}"),
+    );
+    let result = match evaluate_spf(domain, ip, &mut 0) {
+        Ok(result) => result,
+        Err(error) => {
+            return log_warning(&format!("SPF check for {domain} failed: {error:#}"));
+        }
+    };
+    let message = format!("SPF {result} for {domain} from {ip}");
+    if result != SpfResult::Pass {
+        return log_warning(&message);
+    }
+    emit_log(
+        "info",
+        "spf_result",
+        &message,
+        &[
+            ("domain", json_string(domain)),
+            ("ip", json_string(&ip.to_string())),
+            ("result", json_string(&result.to_string())),
+        ],
+    );
+    Ok(())
+}
+
+/// Local address the OS routes through to reach the SMTP server. Behind NAT
+/// this is not the address receivers see, hence `--spf-ip`.
+fn outbound_ip(conn: &Connection) -> Result<IpAddr> {
+    let Endpoint::Tcp { host, port } = &conn.endpoint else {
+        return Err(anyhow!(
+            "a Unix socket has no outbound address; pass --spf-ip"
+        ));
+    };
+    let remote = (host.as_str(), *port)
+        .to_socket_addrs()
+        .with_context(|| format!("failed to resolve {host}"))?
+        .next()
+        .ok_or_else(|| anyhow!("{host} did not resolve to an address"))?;
+    let bind = if remote.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    // Connecting a UDP socket sends nothing; it only selects the route.
+    let socket = UdpSocket::bind(bind).context("failed to open a socket")?;
+    socket
+        .connect(remote)
+        .with_context(|| format!("no route to {host}"))?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// Evaluates the `v=spf1` record of `domain` for `ip`. Macros are rejected and
+/// `ptr` never matches; `lookups` counts DNS-querying terms across includes.
+fn evaluate_spf(domain: &str, ip: IpAddr, lookups: &mut usize) -> Result<SpfResult> {
+    let records = lookup_txt(domain)?
+        .into_iter()
+        .filter(|record| {
+            let version = record.split_whitespace().next().unwrap_or_default();
+            version.eq_ignore_ascii_case("v=spf1")
+        })
+        .collect::<Vec<_>>();
+    let record = match records.as_slice() {
+        [] => return Ok(SpfResult::None),
+        [record] => record,
+        _ => return Err(anyhow!("{domain} publishes more than one SPF record")),
+    };
+
+    let mut redirect = None;
+    for term in record.split_whitespace().skip(1) {
+        if let Some((name, value)) = term.split_once('=')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        {
+            if name.eq_ignore_ascii_case("redirect") {
+                redirect = Some(value);
+            }
+            continue;
+        }
+        if term.contains('%') {
+            return Err(anyhow!("SPF macros are not supported ({term})"));
+        }
+        let (qualifier, mechanism) = match term.chars().next().and_then(SpfResult::from_qualifier) {
+            Some(qualifier) => (qualifier, &term[1..]),
+            None => (SpfResult::Pass, term),
+        };
+        let (name, rest) =
+            mechanism.split_at(mechanism.find([':', '/']).unwrap_or(mechanism.len()));
+        let rest = rest.strip_prefix(':').unwrap_or(rest);
+        let (target, cidr) = match rest.split_once('/') {
+            Some((target, cidr)) => (target, Some(cidr)),
+            None => (rest, None),
+        };
+        let target = if target.is_empty() { domain } else { target };
+
+        let matched = match name.to_ascii_lowercase().as_str() {
+            "all" => true,
+            kind @ ("ip4" | "ip6") => {
+                let network = target
+                    .parse::<IpAddr>()
+                    .ok()
+                    .filter(|network| network.is_ipv4() == (kind == "ip4"))
+                    .ok_or_else(|| anyhow!("invalid SPF term {term}"))?;
+                let max = if network.is_ipv4() { 32 } else { 128 };
+                ip_in_network(ip, network, spf_prefix(cidr, max, term)?)
+            }
+            kind @ ("a" | "mx") => {
+                count_spf_lookup(lookups)?;
+                let (v4, v6) = match cidr.map(|cidr| (cidr, cidr.strip_prefix('/'))) {
+                    None => (None, None),
+                    Some((_, Some(v6))) => (None, Some(v6)),
+                    Some((cidr, None)) => match cidr.split_once("//") {
+                        Some((v4, v6)) => (Some(v4), Some(v6)),
+                        None => (Some(cidr), None),
+                    },
+                };
+                let (v4, v6) = (spf_prefix(v4, 32, term)?, spf_prefix(v6, 128, term)?);
+                let hosts = if kind == "a" {
+                    vec![target.to_string()]
+                } else {
+                    lookup_mx(target)?
+                };
+                hosts
+                    .iter()
+                    .flat_map(|host| host_addresses(host))
+                    .any(|addr| ip_in_network(ip, addr, if addr.is_ipv4() { v4 } else { v6 }))
+            }
+            "include" => {
+                count_spf_lookup(lookups)?;
+                match evaluate_spf(target, ip, lookups)? {
+                    SpfResult::Pass => true,
+                    SpfResult::None => {
+                        return Err(anyhow!("{target}, included by {domain}, has no SPF record"));
+                    }
+                    _ => false,
+                }
+            }
+            "exists" => {
+                count_spf_lookup(lookups)?;
+                !host_addresses(target).is_empty()
+            }
+            "ptr" => {
+                count_spf_lookup(lookups)?;
+                false
+            }
+            _ => return Err(anyhow!("unknown SPF mechanism {term}")),
+        };
+        if matched {
+            return Ok(qualifier);
+        }
+    }
+
+    match redirect {
+        Some(target) => {
+            count_spf_lookup(lookups)?;
+            match evaluate_spf(target, ip, lookups)? {
+                SpfResult::None => Err(anyhow!(
+                    "{target}, the SPF redirect of {domain}, has no SPF record"
+                )),
+                result => Ok(result),
+            }
+        }
+        None => Ok(SpfResult::Neutral),
+    }
+}
+
+fn count_spf_lookup(lookups: &mut usize) -> Result<()> {
+    *lookups += 1;
+    if *lookups > SPF_LOOKUP_LIMIT {
+        return Err(anyhow!(
+            "SPF evaluation needs more than {SPF_LOOKUP_LIMIT} DNS lookups"
+        ));
+    }
+    Ok(())
+}
+
+fn spf_prefix(value: Option<&str>, max: u32, term: &str) -> Result<Option<u32>> {
+    value
+        .map(|value| {
+            value
+                .parse::<u32>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| anyhow!("invalid CIDR length in SPF term {term}"))
+        })
+        .transpose()
+}
+
+/// Addresses of `host` from the system resolver; a name that does not resolve
+/// simply matches nothing.
+fn host_addresses(host: &str) -> Vec<IpAddr> {
+    (host, 0)
+        .to_socket_addrs()
+        .map(|addrs| addrs.map(|addr| addr.ip()).collect())
+        .unwrap_or_default()
+}
+
+fn ip_in_network(ip: IpAddr, network: IpAddr, prefix: Option<u32>) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix.unwrap_or(32)).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX
+                .checked_shl(128 - prefix.unwrap_or(128))
+                .unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Returns the TXT records of `name`, joining the character-strings of each record.
+fn lookup_txt(name: &str) -> Result<Vec<String>> {
+    let reply = query_dns(name, DNS_TYPE_TXT)?;
+    dns_answers(&reply, DNS_TYPE_TXT)?
+        .into_iter()
+        .map(|range| {
+            let data = &reply[range];
+            let mut text = Vec::new();
+            let mut offset = 0;
+            while offset < data.len() {
+                let len = data[offset] as usize;
+                text.extend_from_slice(
+                    data.get(offset + 1..offset + 1 + len)
+                        .ok_or_else(|| anyhow!("malformed DNS reply"))?,
+                );
+                offset += 1 + len;
+            }
+            Ok(String::from_utf8_lossy(&text).into_owned())
+        })
+        .collect()
+}
+
+/// Returns the exchange host names from the MX records of `name`.
+fn lookup_mx(name: &str) -> Result<Vec<String>> {
+    let reply = query_dns(name, DNS_TYPE_MX)?;
+    dns_answers(&reply, DNS_TYPE_MX)?
+        .into_iter()
+        // Skips the preference; the exchange may be compressed against the reply.
+        .map(|range| read_dns_name(&reply, range.start + 2))
+        .collect()
+}
+
+const DNS_TYPE_MX: u16 = 15;
+const DNS_TYPE_TXT: u16 = 16;
+
+/// Sends one query for `name` to the first nameserver in `/etc/resolv.conf`
+/// and returns the raw reply, checked against the query id.
+fn query_dns(name: &str, kind: u16) -> Result<Vec<u8>> {
+    let resolv =
+        fs::read_to_string("/etc/resolv.conf").context("failed to read /etc/resolv.conf")?;
+    let nameserver = resolv
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|addr| addr.trim().parse::<IpAddr>().ok())
+        .ok_or_else(|| anyhow!("no nameserver found in /etc/resolv.conf"))?;
+
+    let id = (SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos()
+        ^ process::id()) as u16;
+    let mut query = Vec::with_capacity(64);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired; one question plus an EDNS0 record for larger replies.
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 1]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(anyhow!("invalid DNS name: {name}"));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&kind.to_be_bytes());
+    query.extend_from_slice(&[0, 1]);
+    query.extend_from_slice(&[0, 0, 41, 0x10, 0x00, 0, 0, 0, 0, 0, 0]);
+
+    let bind = if nameserver.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let socket = UdpSocket::bind(bind).context("failed to open DNS socket")?;
+    socket.set_read_timeout(Some(DNS_TIMEOUT))?;
+    socket
+        .connect((nameserver, 53))
+        .with_context(|| format!("failed to reach nameserver {nameserver}"))?;
+    socket.send(&query)?;
+    let mut buf = [0u8; 4096];
+    let len = socket
+        .recv(&mut buf)
+        .with_context(|| format!("no DNS reply from {nameserver}"))?;
+    if buf[..len].get(..2) != Some(&id.to_be_bytes()[..]) {
+        return Err(anyhow!("DNS reply does not match the query"));
+    }
+    Ok(buf[..len].to_vec())
+}
+
+/// Returns where the record data of every answer of type `kind` sits in the
+/// reply; a missing domain yields no answers.
+fn dns_answers(reply: &[u8], kind: u16) -> Result<Vec<Range<usize>>> {
+    let malformed = || anyhow!("malformed DNS reply");
+    let byte = |pos: usize| reply.get(pos).copied().ok_or_else(malformed);
+    let word =
+        |pos: usize| Ok::<_, anyhow::Error>(u16::from_be_bytes([byte(pos)?, byte(pos + 1)?]));
+    let skip_name = |mut pos: usize| {
+        loop {
+            let len = byte(pos)?;
+            if len == 0 {
+                return Ok::<_, anyhow::Error>(pos + 1);
+            }
+            if len & 0xc0 == 0xc0 {
+                return Ok(pos + 2);
+            }
+            pos += 1 + len as usize;
+        }
+    };
+
+    if byte(2)? & 0x02 != 0 {
+        return Err(anyhow!("DNS reply was truncated"));
+    }
+    match byte(3)? & 0x0f {
+        0 => {}
+        3 => return Ok(Vec::new()),
+        rcode => return Err(anyhow!("DNS lookup failed with rcode {rcode}")),
+    }
+
+    let questions = word(4)?;
+    let answers = word(6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(pos)? + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(pos)?;
+        let record_kind = word(pos)?;
+        let rdlen = word(pos + 8)? as usize;
+        let data = pos + 10..pos + 10 + rdlen;
+        if data.end > reply.len() {
+            return Err(malformed());
+        }
+        pos = data.end;
+        if record_kind == kind {
+            records.push(data);
+        }
+    }
+    Ok(records)
+}
+
+/// Reads a possibly compressed domain name starting at `pos`.
+fn read_dns_name(reply: &[u8], mut pos: usize) -> Result<String> {
+    let malformed = || anyhow!("malformed DNS reply");
+    let mut labels = Vec::new();
+    // Bounds pointer chains so a looping reply cannot hang the lookup.
+    for _ in 0..128 {
+        let len = *reply.get(pos).ok_or_else(malformed)? as usize;
+        if len == 0 {
+            return Ok(labels.join("."));
+        }
+        if len & 0xc0 == 0xc0 {
+            let low = *reply.get(pos + 1).ok_or_else(malformed)? as usize;
+            pos = ((len & 0x3f) << 8) | low;
+            continue;
+        }
+        let label = reply.get(pos + 1..pos + 1 + len).ok_or_else(malformed)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len;
+    }
+    Err(malformed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reply header, one `example.com` question, and the given answer records.
+    fn reply(flags: [u8; 2], answers: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut reply = vec![0x12, 0x34, flags[0], flags[1], 0, 1];
+        reply.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+        reply.extend_from_slice(&[0, 0, 0, 0]);
+        reply.extend_from_slice(b"\x07example\x03com\x00\x00\x10\x00\x01");
+        for (kind, data) in answers {
+            // Owner name as a pointer back to the question.
+            reply.extend_from_slice(&[0xc0, 12]);
+            reply.extend_from_slice(&kind.to_be_bytes());
+            reply.extend_from_slice(&[0, 1, 0, 0, 0x0e, 0x10]);
+            reply.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            reply.extend_from_slice(data);
+        }
+        reply
+    }
+
+    #[test]
+    fn finds_answers_of_the_requested_type() {
+        let reply = reply(
+            [0x81, 0x80],
+            &[
+                (DNS_TYPE_TXT, b"\x05v=spf"),
+                (5, b"\xc0\x0c"),
+                (DNS_TYPE_TXT, b"\x02hi"),
+            ],
+        );
+        let answers = dns_answers(&reply, DNS_TYPE_TXT).unwrap();
+        let data = answers
+            .into_iter()
+            .map(|range| &reply[range])
+            .collect::<Vec<_>>();
+        assert_eq!(data, [&b"\x05v=spf"[..], &b"\x02hi"[..]]);
+    }
+
+    #[test]
+    fn missing_domain_has_no_answers() {
+        let reply = reply([0x81, 0x83], &[]);
+        assert!(dns_answers(&reply, DNS_TYPE_TXT).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_failed_truncated_and_short_replies() {
+        assert!(dns_answers(&reply([0x81, 0x82], &[]), DNS_TYPE_TXT).is_err());
+        assert!(dns_answers(&reply([0x83, 0x80], &[]), DNS_TYPE_TXT).is_err());
+        let mut short = reply([0x81, 0x80], &[(DNS_TYPE_TXT, b"\x02hi")]);
+        short.truncate(short.len() - 1);
+        assert!(dns_answers(&short, DNS_TYPE_TXT).is_err());
+    }
+
+    #[test]
+    fn reads_compressed_names() {
+        let reply = reply([0x81, 0x80], &[(DNS_TYPE_MX, b"\x00\x0a\x02mx\xc0\x0c")]);
+        let answer = dns_answers(&reply, DNS_TYPE_MX).unwrap().remove(0);
+        assert_eq!(
+            read_dns_name(&reply, answer.start + 2).unwrap(),
+            "mx.example.com"
+        );
+    }
+
+    #[test]
+    fn rejects_looping_name_pointers() {
+        let reply = [0xc0, 0x02, 0xc0, 0x00];
+        assert!(read_dns_name(&reply, 0).is_err());
+    }
+
+    #[test]
+    fn matches_ip_networks() {
+        let ip = |text: &str| text.parse::<IpAddr>().unwrap();
+        assert!(ip_in_network(ip("192.0.2.77"), ip("192.0.2.0"), Some(24)));
+        assert!(!ip_in_network(ip("192.0.3.1"), ip("192.0.2.0"), Some(24)));
+        assert!(ip_in_network(ip("192.0.2.1"), ip("192.0.2.1"), None));
+        assert!(ip_in_network(ip("203.0.113.9"), ip("0.0.0.0"), Some(0)));
+        assert!(ip_in_network(ip("2001:db8::1"), ip("2001:db8::"), Some(32)));
+        assert!(!ip_in_network(ip("192.0.2.1"), ip("::"), Some(0)));
+    }
+
+    #[test]
+    fn parses_spf_prefixes() {
+        assert_eq!(spf_prefix(Some("24"), 32, "ip4:x").unwrap(), Some(24));
+        assert_eq!(spf_prefix(None, 32, "ip4:x").unwrap(), None);
+        assert!(spf_prefix(Some("33"), 32, "ip4:x").is_err());
+        assert!(spf_prefix(Some("x"), 128, "ip6:x").is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    env,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow};
+use url::Url;
+
+/// Connect and I/O timeout for webhook posts and link checks.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Minimal HTTP/1.1 client: POSTs `body` as JSON and expects a 2xx status.
+pub(crate) fn post_json(url: &str, body: &str) -> Result<()> {
+    let status = http_request("POST", url, Some("application/json"), body, "webhook")?;
+    if !(200..300).contains(&status) {
+        return Err(anyhow!("webhook responded with HTTP {status}"));
+    }
+    Ok(())
+}
+
+/// Issues a single HTTP/1.1 request and returns the response status. `label`
+/// names the endpoint in error messages.
+pub(crate) fn http_request(
+    method: &str,
+    url: &str,
+    content_type: Option<&str>,
+    body: &str,
+    label: &str,
+) -> Result<u16> {
+    Ok(http_fetch(method, url, content_type, body, label, None)?.status)
+}
+
+pub(crate) struct HttpResponse {
+    pub(crate) status: u16,
+    headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Like [`http_request`], but also returns the headers and, when `max_body` is
+/// set, a response body of at most that many bytes.
+pub(crate) fn http_fetch(
+    method: &str,
+    url: &str,
+    content_type: Option<&str>,
+    body: &str,
+    label: &str,
+    max_body: Option<u64>,
+) -> Result<HttpResponse> {
+    let url = Url::parse(url).with_context(|| format!("invalid {label} URL: {url}"))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("{label} URL must include a host"))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("unsupported {label} scheme: {}", url.scheme()))?;
+    let mut target = url.path().to_string();
+    if let Some(query) = url.query() {
+        target.push('?');
+        target.push_str(query);
+    }
+    let host_header = match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    };
+    let content_type = content_type
+        .map(|value| format!("Content-Type: {value}\r\n"))
+        .unwrap_or_default();
+    let request = format!(
+        "{method} {target} HTTP/1.1\r\nHost: {host_header}\r\nUser-Agent: wirepost/{}\r\n{content_type}Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        env!("CARGO_PKG_VERSION"),
+        body.len()
+    );
+
+    let addr = (host, port)
+        .to_socket_addrs()
+        .with_context(|| format!("failed to resolve {label} host {host}"))?
+        .next()
+        .ok_or_else(|| anyhow!("failed to resolve {label} host {host}"))?;
+    let stream = TcpStream::connect_timeout(&addr, WEBHOOK_TIMEOUT)
+        .with_context(|| format!("failed to connect to {label} {host_header}"))?;
+    stream.set_read_timeout(Some(WEBHOOK_TIMEOUT))?;
+    stream.set_write_timeout(Some(WEBHOOK_TIMEOUT))?;
+
+    match url.scheme() {
+        "http" => http_exchange(stream, &request, label, max_body),
+        "https" => {
+            let stream = native_tls::TlsConnector::new()?
+                .connect(host, stream)
+                .with_context(|| format!("TLS handshake with {label} {host_header} failed"))?;
+            http_exchange(stream, &request, label, max_body)
+        }
+        scheme => Err(anyhow!("unsupported {label} scheme: {scheme}")),
+    }
+}
+
+fn http_exchange<S: Read + Write>(
+    mut stream: S,
+    request: &str,
+    label: &str,
+    max_body: Option<u64>,
+) -> Result<HttpResponse> {
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+    let mut reader = BufReader::new(stream);
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| anyhow!("malformed {label} response: {}", status_line.trim()))?;
+    let Some(max_body) = max_body else {
+        return Ok(HttpResponse {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        });
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    let mut response = HttpResponse {
+        status,
+        headers,
+        body: Vec::new(),
+    };
+    let too_large = || anyhow!("{label} response is larger than {max_body} bytes");
+    if let Some(length) = response
+        .header("content-length")
+        .and_then(|value| value.parse::<u64>().ok())
+        && length > max_body
+    {
+        return Err(too_large());
+    }
+    let chunked = response
+        .header("transfer-encoding")
+        .is_some_and(|value| value.eq_ignore_ascii_case("chunked"));
+    if chunked {
+        loop {
+            let mut size_line = String::new();
+            reader.read_line(&mut size_line)?;
+            let size_hex = size_line.split(';').next().unwrap_or_default().trim();
+            let size = u64::from_str_radix(size_hex, 16)
+                .map_err(|_| anyhow!("malformed chunked {label} response"))?;
+            if size == 0 {
+                break;
+            }
+            if response.body.len() as u64 + size > max_body {
+                return Err(too_large());
+            }
+            let start = response.body.len();
+            response.body.resize(start + size as usize, 0);
+            reader.read_exact(&mut response.body[start..])?;
+            reader.read_line(&mut String::new())?;
+        }
+    } else {
+        reader
+            .take(max_body.saturating_add(1))
+            .read_to_end(&mut response.body)?;
+        if response.body.len() as u64 > max_body {
+            return Err(too_large());
+        }
+    }
+    Ok(response)
+}
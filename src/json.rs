@@ -0,0 +1,290 @@
+use anyhow::{Result, anyhow};
+
+pub(crate) enum JsonValue {
+    Null,
+    Bool(bool),
+    /// Kept as written so integers are not reformatted as floats.
+    Number(String),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+
+/// Small recursive-descent JSON reader for `--spec-file`.
+pub(crate) struct JsonParser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    pub(crate) fn new(input: &'a str) -> Self {
+        JsonParser { input, pos: 0 }
+    }
+
+    pub(crate) fn parse_document(mut self) -> Result<JsonValue> {
+        let value = self.parse_value()?;
+        self.skip_whitespace();
+        if self.pos < self.input.len() {
+            return Err(self.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    fn error(&self, message: &str) -> anyhow::Error {
+        anyhow!("{message} at byte {}", self.pos)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<()> {
+        self.skip_whitespace();
+        if self.peek() != Some(byte) {
+            return Err(self.error(&format!("expected '{}'", byte as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn parse_value(&mut self) -> Result<JsonValue> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => self.parse_object(),
+            Some(b'[') => self.parse_array(),
+            Some(b'"') => Ok(JsonValue::String(self.parse_string()?)),
+            Some(b'-' | b'0'..=b'9') => self.parse_number(),
+            _ => {
+                for (word, value) in [
+                    ("true", JsonValue::Bool(true)),
+                    ("false", JsonValue::Bool(false)),
+                    ("null", JsonValue::Null),
+                ] {
+                    if self.input[self.pos..].starts_with(word) {
+                        self.pos += word.len();
+                        return Ok(value);
+                    }
+                }
+                Err(self.error("expected a JSON value"))
+            }
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<JsonValue> {
+        self.expect(b'{')?;
+        let mut entries = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(JsonValue::Object(entries));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.parse_string()?;
+            self.expect(b':')?;
+            entries.push((key, self.parse_value()?));
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(JsonValue::Object(entries));
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<JsonValue> {
+        self.expect(b'[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(JsonValue::Array(items));
+        }
+        loop {
+            items.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(JsonValue::Array(items));
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<JsonValue> {
+        let start = self.pos;
+        while matches!(
+            self.peek(),
+            Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')
+        ) {
+            self.pos += 1;
+        }
+        let number = &self.input[start..self.pos];
+        number
+            .parse::<f64>()
+            .map_err(|_| self.error("invalid number"))?;
+        Ok(JsonValue::Number(number.to_string()))
+    }
+
+    fn parse_string(&mut self) -> Result<String> {
+        if self.peek() != Some(b'"') {
+            return Err(self.error("expected a string"));
+        }
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            let rest = &self.input[self.pos..];
+            let end = rest
+                .find(['"', '\\'])
+                .ok_or_else(|| self.error("unterminated string"))?;
+            out.push_str(&rest[..end]);
+            self.pos += end + 1;
+            if rest.as_bytes()[end] == b'"' {
+                return Ok(out);
+            }
+            let escape = self
+                .peek()
+                .ok_or_else(|| self.error("unterminated string"))?;
+            self.pos += 1;
+            match escape {
+                b'"' => out.push('"'),
+                b'\\' => out.push('\\'),
+                b'/' => out.push('/'),
+                b'b' => out.push('\u{8}'),
+                b'f' => out.push('\u{c}'),
+                b'n' => out.push('\n'),
+                b'r' => out.push('\r'),
+                b't' => out.push('\t'),
+                b'u' => {
+                    let mut code = self.parse_hex4()?;
+                    if (0xd800..0xdc00).contains(&code) && self.input[self.pos..].starts_with("\\u")
+                    {
+                        self.pos += 2;
+                        let low = self.parse_hex4()?;
+                        code =
+                            0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+                    }
+                    out.push(char::from_u32(code).ok_or_else(|| self.error("invalid \\u escape"))?);
+                }
+                _ => return Err(self.error("invalid escape")),
+            }
+        }
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32> {
+        let hex = self
+            .input
+            .get(self.pos..self.pos + 4)
+            .ok_or_else(|| self.error("truncated \\u escape"))?;
+        let code = u32::from_str_radix(hex, 16).map_err(|_| self.error("invalid \\u escape"))?;
+        self.pos += 4;
+        Ok(code)
+    }
+}
+
+pub(crate) fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for ch in value.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            ch if ch.is_control() => out.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => out.push(ch),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Result<JsonValue> {
+        JsonParser::new(input).parse_document()
+    }
+
+    #[test]
+    fn parses_nested_documents() {
+        let value = parse(
+            r#" {"to": ["a@example.com", "b@example.com"], "n": 42, "ok": true, "x": null} "#,
+        )
+        .unwrap();
+        let JsonValue::Object(fields) = value else {
+            panic!("expected an object");
+        };
+        let keys = fields
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, ["to", "n", "ok", "x"]);
+        assert!(matches!(&fields[0].1, JsonValue::Array(items) if items.len() == 2));
+        assert!(matches!(&fields[1].1, JsonValue::Number(number) if number == "42"));
+        assert!(matches!(fields[2].1, JsonValue::Bool(true)));
+        assert!(matches!(fields[3].1, JsonValue::Null));
+    }
+
+    #[test]
+    fn keeps_numbers_as_written() {
+        assert!(matches!(parse("1.50").unwrap(), JsonValue::Number(number) if number == "1.50"));
+        assert!(matches!(parse("-2e3").unwrap(), JsonValue::Number(number) if number == "-2e3"));
+        assert!(parse("1.2.3").is_err());
+    }
+
+    #[test]
+    fn decodes_string_escapes() {
+        let value = parse(r#""a\"b\\c\/d\n\té😀""#).unwrap();
+        assert!(matches!(value, JsonValue::String(text) if text == "a\"b\\c/d\n\té😀"));
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        for input in [
+            r#"{"a": 1"#,
+            r#"{"a" 1}"#,
+            r#"[1, 2,]"#,
+            r#""unterminated"#,
+            r#""\q""#,
+            r#""\u12""#,
+            "[] []",
+            "nope",
+        ] {
+            assert!(parse(input).is_err(), "accepted {input:?}");
+        }
+    }
+
+    #[test]
+    fn error_reports_the_offset() {
+        let Err(error) = parse("[1, }") else {
+            panic!("accepted a malformed array");
+        };
+        assert_eq!(error.to_string(), "expected a JSON value at byte 4");
+    }
+
+    #[test]
+    fn json_string_round_trips() {
+        let text = "quote \" backslash \\ newline \n bell \u{7} é";
+        let encoded = json_string(text);
+        assert_eq!(
+            encoded,
+            "\"quote \\\" backslash \\\\ newline \\n bell \\u0007 é\""
+        );
+        assert!(matches!(parse(&encoded).unwrap(), JsonValue::String(decoded) if decoded == text));
+    }
+}
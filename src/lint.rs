@@ -0,0 +1,221 @@
+use anyhow::{Result, anyhow};
+use mime_guess::mime;
+
+use crate::header_param;
+
+/// Prints every MIME structure problem in the formatted message and fails
+/// when there is at least one.
+pub(crate) fn lint_message(raw: &[u8]) -> Result<()> {
+    let mut issues = Vec::new();
+    lint_part(raw, "message", &mut Vec::new(), &mut issues);
+    if issues.is_empty() {
+        println!("MIME structure OK");
+        return Ok(());
+    }
+    for issue in &issues {
+        println!("{issue}");
+    }
+    let noun = if issues.len() == 1 {
+        "problem"
+    } else {
+        "problems"
+    };
+    Err(anyhow!("found {} MIME structure {noun}", issues.len()))
+}
+
+fn lint_part(part: &[u8], path: &str, boundaries: &mut Vec<String>, issues: &mut Vec<String>) {
+    let (headers, body) = split_header_block(part);
+    let content_type =
+        header_value(headers, "Content-Type").unwrap_or_else(|| "text/plain".to_string());
+    let mime_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if mime_type.parse::<mime::Mime>().is_err() {
+        issues.push(format!("{path}: invalid content type {mime_type:?}"));
+        return;
+    }
+
+    let Some(subtype) = mime_type.strip_prefix("multipart/") else {
+        let attachment = header_value(headers, "Content-Disposition").is_some_and(|value| {
+            value
+                .trim_start()
+                .to_ascii_lowercase()
+                .starts_with("attachment")
+        });
+        if attachment && body.iter().all(u8::is_ascii_whitespace) {
+            issues.push(format!("{path}: attachment with empty body"));
+        }
+        return;
+    };
+    let Some(boundary) = header_param(&content_type, "boundary").filter(|b| !b.is_empty()) else {
+        issues.push(format!("{path}: {mime_type} without a boundary"));
+        return;
+    };
+    if boundaries.contains(&boundary) {
+        issues.push(format!(
+            "{path}: boundary {boundary:?} is used by another multipart"
+        ));
+    }
+    boundaries.push(boundary.clone());
+
+    let (parts, closed) = split_multipart(body, &boundary);
+    if !closed {
+        issues.push(format!(
+            "{path}: {mime_type} is missing its closing boundary"
+        ));
+    }
+    match (subtype, parts.len()) {
+        (_, 0) => issues.push(format!("{path}: {mime_type} with no parts")),
+        ("alternative", 1) => issues.push(format!("{path}: {mime_type} with a single part")),
+        _ => {}
+    }
+    for (index, child) in parts.iter().enumerate() {
+        let child_path = if path == "message" {
+            format!("part {}", index + 1)
+        } else {
+            format!("{path}.{}", index + 1)
+        };
+        lint_part(child, &child_path, boundaries, issues);
+    }
+}
+
+/// Splits a MIME entity at the blank line ending its header block.
+fn split_header_block(part: &[u8]) -> (&[u8], &[u8]) {
+    let mut pos = 0;
+    while pos < part.len() {
+        let end = part[pos..]
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .map_or(part.len(), |offset| pos + offset + 1);
+        if matches!(&part[pos..end], b"\r\n" | b"\n") {
+            return (&part[..pos], &part[end..]);
+        }
+        pos = end;
+    }
+    (part, &[])
+}
+
+/// Returns the body parts between `--boundary` delimiters, and whether the
+/// closing `--boundary--` delimiter was found.
+fn split_multipart<'a>(body: &'a [u8], boundary: &str) -> (Vec<&'a [u8]>, bool) {
+    let delimiter = format!("--{boundary}");
+    let mut parts = Vec::new();
+    let mut start = None;
+    let mut pos = 0;
+    while pos < body.len() {
+        let end = body[pos..]
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .map_or(body.len(), |offset| pos + offset + 1);
+        let line = body[pos..end].trim_ascii_end();
+        if let Some(rest) = line.strip_prefix(delimiter.as_bytes()) {
+            let closing = rest.starts_with(b"--");
+            if closing || rest.iter().all(u8::is_ascii_whitespace) {
+                if let Some(start) = start {
+                    // The line break before a delimiter belongs to the delimiter.
+                    let part: &[u8] = &body[start..pos];
+                    let part = part.strip_suffix(b"\n").unwrap_or(part);
+                    parts.push(part.strip_suffix(b"\r").unwrap_or(part));
+                }
+                if closing {
+                    return (parts, true);
+                }
+                start = Some(end);
+            }
+        }
+        pos = end;
+    }
+    if let Some(start) = start {
+        parts.push(&body[start..]);
+    }
+    (parts, false)
+}
+
+/// Returns the unfolded value of the first `name` header in a formatted message.
+pub(crate) fn header_value(raw: &[u8], name: &str) -> Option<String> {
+    let text = String::from_utf8_lossy(raw);
+    let mut lines = text.lines().take_while(|line| !line.is_empty());
+    while let Some(line) = lines.next() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        if !key.trim().eq_ignore_ascii_case(name) {
+            continue;
+        }
+        let mut value = value.trim().to_string();
+        for continuation in lines.by_ref() {
+            if !continuation.starts_with([' ', '\t']) {
+                break;
+            }
+            value.push(' ');
+            value.push_str(continuation.trim());
+        }
+        return Some(value);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_the_header_block() {
+        let (headers, body) = split_header_block(b"A: 1\r\nB: 2\r\n\r\nbody\r\n");
+        assert_eq!(headers, b"A: 1\r\nB: 2\r\n");
+        assert_eq!(body, b"body\r\n");
+
+        let (headers, body) = split_header_block(b"A: 1\n");
+        assert_eq!(headers, b"A: 1\n");
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn splits_multipart_bodies() {
+        let body =
+            b"preamble\r\n--b1\r\nA: 1\r\n\r\none\r\n--b1\r\n\r\ntwo\r\n--b1--\r\nepilogue\r\n";
+        let (parts, closed) = split_multipart(body, "b1");
+        assert!(closed);
+        assert_eq!(parts, [&b"A: 1\r\n\r\none"[..], &b"\r\ntwo"[..]]);
+    }
+
+    #[test]
+    fn reports_a_missing_closing_boundary() {
+        let (parts, closed) = split_multipart(b"--b1\nA: 1\n\none\n--b1x\n", "b1");
+        assert!(!closed);
+        assert_eq!(parts, [&b"A: 1\n\none\n--b1x\n"[..]]);
+    }
+
+    #[test]
+    fn header_value_unfolds_and_stops_at_the_body() {
+        let raw = b"Subject: a\r\n  long subject\r\nmessage-id: <x@y>\r\n\r\nFrom: body\r\n";
+        assert_eq!(
+            header_value(raw, "subject").as_deref(),
+            Some("a long subject")
+        );
+        assert_eq!(header_value(raw, "Message-ID").as_deref(), Some("<x@y>"));
+        assert_eq!(header_value(raw, "From"), None);
+    }
+
+    #[test]
+    fn lints_nested_multiparts() {
+        let raw = b"Content-Type: multipart/mixed; boundary=\"o\"\r\n\r\n\
+--o\r\nContent-Type: multipart/alternative; boundary=\"i\"\r\n\r\n--i\r\n\r\ntext\r\n--i--\r\n\
+--o\r\nContent-Type: multipart/related; boundary=\"i\"\r\n\r\n--i\r\n\r\nhtml\r\n--i--\r\n\
+--o\r\nContent-Disposition: attachment\r\n\r\n\r\n";
+        let mut issues = Vec::new();
+        lint_part(raw, "message", &mut Vec::new(), &mut issues);
+        assert_eq!(
+            issues,
+            [
+                "message: multipart/mixed is missing its closing boundary",
+                "part 1: multipart/alternative with a single part",
+                "part 2: boundary \"i\" is used by another multipart",
+                "part 3: attachment with empty body",
+            ]
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    fmt,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, anyhow};
+use tracing::{
+    Event, Metadata, Subscriber,
+    field::{Field, Visit},
+    span,
+};
+
+use crate::json::json_string;
+
+/// Set from `--log-format json` so every diagnostic line becomes a JSON object.
+pub(crate) static JSON_LOGS: AtomicBool = AtomicBool::new(false);
+
+/// Set from `--name`; tags every diagnostic line.
+pub(crate) static INSTANCE_NAME: Mutex<Option<String>> = Mutex::new(None);
+
+/// Set from `--strict` so [`log_warning`] fails instead of printing.
+pub(crate) static STRICT: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn log_verbose(enabled: bool, message: &str) {
+    log_event(enabled, "message", message, &[]);
+}
+
+/// Verbose-level line with machine-readable `fields`, whose values must already
+/// be JSON-encoded.
+pub(crate) fn log_event(enabled: bool, event: &str, message: &str, fields: &[(&str, String)]) {
+    if enabled {
+        emit_log("debug", event, message, fields);
+    }
+}
+
+/// Prints a warning, or returns it as an error under `--strict`.
+pub(crate) fn log_warning(message: &str) -> Result<()> {
+    if STRICT.load(Ordering::Relaxed) {
+        return Err(anyhow!("{message} (warnings are errors with --strict)"));
+    }
+    emit_log("warn", "warning", message, &[]);
+    Ok(())
+}
+
+pub(crate) fn emit_log(level: &str, event: &str, message: &str, fields: &[(&str, String)]) {
+    let name = INSTANCE_NAME
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .clone();
+    if !JSON_LOGS.load(Ordering::Relaxed) {
+        let tag = match &name {
+            Some(name) => format!("[wirepost:{name}]"),
+            None => "[wirepost]".to_string(),
+        };
+        match level {
+            "warn" => eprintln!("{tag} warning: {message}"),
+            _ => eprintln!("{tag} {message}"),
+        }
+        return;
+    }
+    let mut line = format!(
+        "{{\"ts\":{},\"level\":{},\"event\":{},\"message\":{}",
+        json_string(&format_timestamp(SystemTime::now())),
+        json_string(level),
+        json_string(event),
+        json_string(message.trim())
+    );
+    if let Some(name) = &name {
+        line.push_str(&format!(",\"name\":{}", json_string(name)));
+    }
+    for (key, value) in fields {
+        line.push_str(&format!(",{}:{value}", json_string(key)));
+    }
+    line.push('}');
+    eprintln!("{line}");
+}
+
+/// RFC 3339 UTC timestamp with millisecond precision.
+pub(crate) fn format_timestamp(time: SystemTime) -> String {
+    let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = elapsed.as_secs();
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    // Civil-from-days conversion (Howard Hinnant's algorithm).
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60,
+        elapsed.subsec_millis()
+    )
+}
+
+/// Prints lettre's SMTP client events as a `C:`/`S:` dialogue, hiding AUTH
+/// payloads and the message content itself.
+#[derive(Default)]
+pub(crate) struct SmtpTraceSubscriber {
+    state: Mutex<TraceState>,
+}
+
+#[derive(Default)]
+struct TraceState {
+    mask_next_write: bool,
+    data_next_write: bool,
+}
+
+#[derive(Default)]
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{value:?}");
+        }
+    }
+}
+
+impl SmtpTraceSubscriber {
+    fn describe(&self, message: &str) -> String {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(written) = message.strip_prefix("Wrote: ") {
+            let line = written
+                .trim_start_matches("<CRLF>")
+                .trim_end_matches("<CRLF>");
+            let line = if state.data_next_write {
+                state.data_next_write = false;
+                format!("<message content, {} bytes>", written.len())
+            } else if state.mask_next_write {
+                state.mask_next_write = false;
+                "****".to_string()
+            } else {
+                mask_auth_command(line)
+            };
+            format!("C: {line}")
+        } else if let Some(response) = message.strip_prefix("<< ") {
+            // lettre logs the accumulated buffer, so only the newest line is new.
+            let buffered = response.trim_end_matches("<CRLF>");
+            let line = buffered.rsplit("<CRLF>").next().unwrap_or(buffered);
+            state.mask_next_write = line.starts_with("334 ");
+            state.data_next_write = line.starts_with("354");
+            format!("S: {line}")
+        } else {
+            message.to_string()
+        }
+    }
+}
+
+fn mask_auth_command(line: &str) -> String {
+    let mut parts = line.splitn(3, ' ');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(verb), Some(mechanism), Some(_)) if verb.eq_ignore_ascii_case("AUTH") => {
+            format!("{verb} {mechanism} ****")
+        }
+        _ => line.to_string(),
+    }
+}
+
+impl Subscriber for SmtpTraceSubscriber {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.target().starts_with("lettre")
+    }
+
+    fn new_span(&self, _span: &span::Attributes<'_>) -> span::Id {
+        span::Id::from_u64(1)
+    }
+
+    fn record(&self, _span: &span::Id, _values: &span::Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        emit_log("trace", "smtp", &self.describe(&visitor.0), &[]);
+    }
+
+    fn enter(&self, _span: &span::Id) {}
+
+    fn exit(&self, _span: &span::Id) {}
+}
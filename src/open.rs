@@ -0,0 +1,50 @@
+use std::{
+    ffi::OsStr,
+    io,
+    process::{Command, Stdio},
+};
+
+/// Opens `path` in the default application for its type, like `open::that` from
+/// the `open` crate, whose signature it keeps. Each launcher known for the
+/// platform is tried in turn; the error of the last one is returned when none
+/// succeeds.
+pub(crate) fn that(path: impl AsRef<OsStr>) -> io::Result<()> {
+    let mut last_error = None;
+    for &(program, args) in launchers() {
+        let status = Command::new(program)
+            .args(args)
+            .arg(path.as_ref())
+            .stdin(Stdio::null())
+            .status();
+        match status {
+            Ok(status) if status.success() => return Ok(()),
+            Ok(status) => {
+                last_error = Some(io::Error::other(format!("{program} exited with {status}")))
+            }
+            Err(error) => last_error = Some(error),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::from(io::ErrorKind::NotFound)))
+}
+
+#[cfg(target_os = "macos")]
+fn launchers() -> &'static [(&'static str, &'static [&'static str])] {
+    &[("open", &[])]
+}
+
+#[cfg(windows)]
+fn launchers() -> &'static [(&'static str, &'static [&'static str])] {
+    // `start` takes the first quoted argument as the window title, hence the "".
+    &[("cmd", &["/C", "start", ""])]
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn launchers() -> &'static [(&'static str, &'static [&'static str])] {
+    &[
+        ("xdg-open", &[]),
+        ("gio", &["open"]),
+        ("gnome-open", &[]),
+        ("kde-open", &[]),
+        ("wslview", &[]),
+    ]
+}
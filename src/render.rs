@@ -0,0 +1,1318 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, anyhow};
+use regex::Regex;
+
+use crate::{
+    Addresses, Args, BodySource, RenderedContent, Rng, TemplateVars, format_size,
+    http::http_request,
+    json::json_string,
+    log::{log_verbose, log_warning},
+    new_message_id, resolve_from,
+};
+
+const META_HEADER: &str = "X-Wirepost-Meta";
+
+/// Encodes the `--meta key=value` entries as one `X-Wirepost-Meta` header
+/// holding a JSON object, rendering each value as a template. A repeated key
+/// keeps its last value.
+fn meta_header(args: &Args, vars: &TemplateVars) -> Result<String> {
+    let mut fields: Vec<(&str, String)> = Vec::new();
+    for entry in &args.meta {
+        let (key, value) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid --meta, expected key=value"))?;
+        let key = key.trim();
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        {
+            return Err(anyhow!("invalid --meta key: {key:?}"));
+        }
+        let value = render_template(value, vars, args)?;
+        fields.retain(|(existing, _)| *existing != key);
+        fields.push((key, value));
+    }
+    let object = fields
+        .iter()
+        .map(|(key, value)| format!("{}:{}", json_string(key), json_string(value)))
+        .collect::<Vec<_>>()
+        .join(",");
+    // Escapes non-ASCII so the header is not RFC 2047-encoded into invalid JSON.
+    let mut ascii = String::with_capacity(object.len());
+    for ch in object.chars() {
+        if ch.is_ascii() {
+            ascii.push(ch);
+        } else {
+            for unit in ch.encode_utf16(&mut [0; 2]) {
+                ascii.push_str(&format!("\\u{unit:04x}"));
+            }
+        }
+    }
+    Ok(format!("{META_HEADER}: {{{ascii}}}"))
+}
+
+/// Parses `key=value` template variables.
+pub fn parse_vars(entries: &[String]) -> Result<TemplateVars> {
+    let mut vars = HashMap::new();
+    for entry in entries {
+        let (key, value) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid --var, expected key=value"))?;
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(anyhow!("template variable names cannot be empty"));
+        }
+        vars.insert(key.to_string(), value.to_string());
+    }
+    Ok(vars)
+}
+
+/// Reads `key=path` template variables from disk.
+pub fn load_var_files(entries: &[String], keep_newline: bool) -> Result<TemplateVars> {
+    let mut vars = HashMap::new();
+    for entry in entries {
+        let (key, path) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid --var-file, expected key=path"))?;
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(anyhow!("template variable names cannot be empty"));
+        }
+        let value = fs::read_to_string(path)
+            .with_context(|| format!("failed to read variable `{key}` from {path}"))?;
+        let value = if keep_newline {
+            value.as_str()
+        } else {
+            value
+                .strip_suffix("\r\n")
+                .or_else(|| value.strip_suffix('\n'))
+                .unwrap_or(&value)
+        };
+        vars.insert(key.to_string(), value.to_string());
+    }
+    Ok(vars)
+}
+
+const VARIABLE_PATTERN: &str = r"\{\{\s*([A-Za-z0-9_\-\.]+)\s*\}\}";
+const ENV_PATTERN: &str = r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}";
+
+/// Replaces `{{key}}` placeholders, leaving unknown ones untouched.
+pub fn apply_template(input: &str, vars: &TemplateVars) -> String {
+    if vars.is_empty() {
+        return input.to_string();
+    }
+
+    let re = Regex::new(VARIABLE_PATTERN).expect("valid variable regex");
+
+    re.replace_all(input, |caps: &regex::Captures| {
+        let key = &caps[1];
+        if let Some(value) = vars.get(key) {
+            value.clone()
+        } else {
+            caps.get(0)
+                .map(|m| m.as_str().to_string())
+                .unwrap_or_default()
+        }
+    })
+    .into_owned()
+}
+
+fn ensure_vars_defined(input: &str, vars: &TemplateVars) -> Result<()> {
+    let re = Regex::new(VARIABLE_PATTERN).expect("valid variable regex");
+    match re
+        .captures_iter(input)
+        .find(|caps| !vars.contains_key(&caps[1]))
+    {
+        Some(caps) => Err(anyhow!("template variable `{}` is not defined", &caps[1])),
+        None => Ok(()),
+    }
+}
+
+fn expand_env(input: &str, strict: bool) -> Result<String> {
+    let re = Regex::new(ENV_PATTERN).expect("valid env regex");
+    let mut missing = None;
+    let expanded = re.replace_all(input, |caps: &regex::Captures| match env::var(&caps[1]) {
+        Ok(value) => value,
+        Err(_) => {
+            missing.get_or_insert_with(|| caps[1].to_string());
+            caps[0].to_string()
+        }
+    });
+    match missing {
+        Some(name) if strict => Err(anyhow!("environment variable `{name}` is not set")),
+        _ => Ok(expanded.into_owned()),
+    }
+}
+
+/// Applies variables and, when enabled, environment expansion and strict checks.
+/// `${NAME}` is expanded in the template itself before the variables go in, so
+/// variable values, which may come from recipient data, never reach the
+/// environment.
+pub fn render_template(input: &str, vars: &TemplateVars, args: &Args) -> Result<String> {
+    if args.strict_vars {
+        ensure_vars_defined(input, vars)?;
+    }
+    if args.expand_env {
+        Ok(apply_template(&expand_env(input, args.strict_vars)?, vars))
+    } else {
+        Ok(apply_template(input, vars))
+    }
+}
+
+/// Renders the subject, bodies, headers, and attachment names.
+pub fn render_content(
+    args: &Args,
+    vars: &TemplateVars,
+    sources: &BodySource,
+    rng: &mut Rng,
+) -> Result<RenderedContent> {
+    let render = |value: &String| render_template(value, vars, args);
+    let signature = sources
+        .signature
+        .as_ref()
+        .map(render)
+        .transpose()?
+        .map(|signature| signature.trim_end().to_string());
+    if args.wrap_text == Some(0) {
+        return Err(anyhow!("--wrap-text must be at least 1"));
+    }
+    let mut headers = sources
+        .headers
+        .iter()
+        .chain(&args.headers)
+        .map(render)
+        .collect::<Result<Vec<_>>>()?;
+    if !args.meta.is_empty() {
+        headers.push(meta_header(args, vars)?);
+    }
+    let pixel = match (&args.tracking_pixel, &sources.html) {
+        (Some(url), Some(_)) => Some(tracking_pixel(args, vars, url, &mut headers, rng)?),
+        _ => None,
+    };
+    let preheader = args.preheader.as_ref().map(render).transpose()?;
+    let html = sources.html.as_ref().map(render).transpose()?;
+    let title = match &sources.html {
+        Some(html) if args.subject_from_title && args.subject.is_empty() => html_title(html),
+        _ => None,
+    };
+    let mut subject = render(title.as_ref().unwrap_or(&args.subject))?;
+    let alert_prefix = args.alert.then(|| ALERT_SUBJECT_PREFIX.to_string());
+    if let Some(prefix) = args.subject_prefix.as_ref().or(alert_prefix.as_ref()) {
+        let prefix = render(prefix)?;
+        if !(args.subject_prefix_once && subject.starts_with(&prefix)) {
+            subject = format!("{prefix} {subject}");
+        }
+    }
+    let rendered = RenderedContent {
+        subject,
+        text: sources
+            .text
+            .as_ref()
+            .map(render)
+            .transpose()?
+            .map(|text| match args.wrap_text {
+                Some(width) => wrap_text(&text, width),
+                None => text,
+            })
+            .map(|text| match &signature {
+                Some(signature) => {
+                    let text = text.trim_end_matches(['\r', '\n']);
+                    let text = format!("{text}\n-- \n{signature}\n");
+                    if args.crlf { to_crlf(&text) } else { text }
+                }
+                None => text,
+            }),
+        html: html.map(|html| {
+            let html = match &signature {
+                Some(signature) if args.signature_html => append_html_signature(&html, signature),
+                _ => html,
+            };
+            let html = match &args.link_wrap {
+                Some(base) => wrap_links(&html, base),
+                None => html,
+            };
+            let html = match &pixel {
+                Some(pixel) => insert_before_body_end(&html, pixel),
+                None => html,
+            };
+            let html = match &preheader {
+                Some(text) => insert_after_body_start(&html, &preheader_block(text)),
+                None => html,
+            };
+            let html = if args.inline_css {
+                inline_css(&html)
+            } else {
+                html
+            };
+            if !args.minify_html {
+                return html;
+            }
+            let minified = minify_html(&html);
+            log_verbose(
+                args.verbose,
+                &format!(
+                    "Minified HTML body from {} to {} (saved {})",
+                    format_size(html.len()),
+                    format_size(minified.len()),
+                    format_size(html.len() - minified.len())
+                ),
+            );
+            minified
+        }),
+        amp_html: sources.amp_html.as_ref().map(render).transpose()?,
+        headers,
+        attachment_names: args
+            .attachment_names
+            .iter()
+            .map(render)
+            .collect::<Result<_>>()?,
+        conditional_attachments: select_conditional_attachments(args, vars)?,
+        content_language: args
+            .content_language
+            .clone()
+            .or_else(|| sources.lang.clone()),
+    };
+    if let Some(limit) = args.max_body_bytes {
+        let size = rendered.text.as_ref().map_or(0, String::len)
+            + rendered.html.as_ref().map_or(0, String::len);
+        if size as u64 > limit {
+            return Err(anyhow!(
+                "rendered text and HTML bodies are {size} bytes, over --max-body-bytes {limit}"
+            ));
+        }
+    }
+    Ok(rendered)
+}
+
+/// Resolves `--attach-if var:path` entries to the paths whose variable is set
+/// and non-blank.
+fn select_conditional_attachments(args: &Args, vars: &TemplateVars) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in &args.conditional_attachments {
+        let (var, path) = entry
+            .split_once(':')
+            .ok_or_else(|| anyhow!("invalid --attach-if, expected var:path, got: {entry}"))?;
+        let var = var.trim();
+        if vars.get(var).is_some_and(|value| !value.trim().is_empty()) {
+            paths.push(PathBuf::from(path));
+        } else {
+            log_verbose(
+                args.verbose,
+                &format!("Skipping attachment {path} because {var} is empty"),
+            );
+        }
+    }
+    Ok(paths)
+}
+
+/// Greedily rewraps each blank-line separated paragraph to `width` columns.
+/// Words longer than the width, such as URLs, get a line of their own rather
+/// than being split, and quoted (`>`) or indented paragraphs are left as-is.
+fn wrap_text(text: &str, width: usize) -> String {
+    let newline = if text.contains("\r\n") { "\r\n" } else { "\n" };
+    let normalized = text.replace("\r\n", "\n");
+    let mut paragraphs = Vec::new();
+    for paragraph in normalized.trim_end_matches('\n').split("\n\n") {
+        let preformatted = paragraph
+            .lines()
+            .any(|line| line.starts_with(['>', ' ', '\t']));
+        if preformatted {
+            paragraphs.push(paragraph.to_string());
+            continue;
+        }
+        let mut lines: Vec<String> = Vec::new();
+        let mut line = String::new();
+        for word in paragraph.split_whitespace() {
+            if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > width {
+                lines.push(std::mem::take(&mut line));
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(word);
+        }
+        lines.push(line);
+        paragraphs.push(lines.join("\n"));
+    }
+
+    let mut wrapped = paragraphs.join("\n\n");
+    if normalized.ends_with('\n') {
+        wrapped.push('\n');
+    }
+    wrapped.replace('\n', newline)
+}
+
+/// Builds the `<img>` tag for --tracking-pixel. The message id exposed to the URL
+/// template comes from the last Message-ID header, the one the message keeps;
+/// without one a new id is generated and added to `headers` so the sent message
+/// carries it.
+fn tracking_pixel(
+    args: &Args,
+    vars: &TemplateVars,
+    url: &str,
+    headers: &mut Vec<String>,
+    rng: &mut Rng,
+) -> Result<String> {
+    let existing = headers.iter().rev().find_map(|header| {
+        let (name, value) = header.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("Message-ID")
+            .then(|| value.trim().trim_matches(['<', '>']).to_string())
+    });
+    let message_id = match existing {
+        Some(id) => id,
+        None => {
+            let from = render_template(&resolve_from(args)?, vars, args)?;
+            let id = new_message_id(&from, rng)?;
+            headers.push(format!("Message-ID: <{id}>"));
+            id
+        }
+    };
+
+    let mut vars = vars.clone();
+    vars.insert("message_id".to_string(), message_id);
+    let url = render_template(url, &vars, args)?;
+    log_verbose(args.verbose, &format!("Adding tracking pixel {url}"));
+    Ok(format!(
+        "<img src=\"{}\" width=\"1\" height=\"1\" alt=\"\" style=\"display:block;border:0;width:1px;height:1px\">\n",
+        url.replace('&', "&amp;").replace('"', "&quot;")
+    ))
+}
+
+/// Points every `href` on `<a>` and `<area>` tags at `base` with the original URL
+/// in the `u` query parameter, leaving `mailto:`, `tel:` and `#` links alone.
+/// Tags are scanned with a quote-aware tokenizer so `>` inside attribute values
+/// and unterminated markup do not corrupt the document.
+fn wrap_links(html: &str, base: &str) -> String {
+    map_tags(html, |tag| wrap_tag_link(tag, base))
+}
+
+/// Rebuilds `html` with every tag replaced by `map(tag)`, leaving text and
+/// comments untouched.
+fn map_tags(html: &str, mut map: impl FnMut(&str) -> String) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        let tail = &rest[start..];
+        let end = if tail.starts_with("<!--") {
+            tail.find("-->").map(|index| index + 3)
+        } else {
+            tag_end(tail)
+        };
+        let Some(end) = end else {
+            rest = tail;
+            break;
+        };
+        out.push_str(&map(&tail[..end]));
+        rest = &tail[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Byte offset just past the `>` closing the tag at the start of `tail`.
+fn tag_end(tail: &str) -> Option<usize> {
+    let mut quote = None;
+    for (index, ch) in tail.char_indices().skip(1) {
+        match (quote, ch) {
+            (Some(open), ch) if ch == open => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(ch),
+            (None, '>') => return Some(index + 1),
+            (None, _) => {}
+        }
+    }
+    None
+}
+
+fn wrap_tag_link(tag: &str, base: &str) -> String {
+    let Some((value_start, value_end, next)) = find_tag_href(tag) else {
+        return tag.to_string();
+    };
+    let original = tag[value_start..value_end].replace("&amp;", "&");
+    if !is_checkable_link(&original) {
+        return tag.to_string();
+    }
+    let separator = if base.contains('?') { '&' } else { '?' };
+    let encoded: String =
+        url::form_urlencoded::byte_serialize(original.trim().as_bytes()).collect();
+    let wrapped = format!("{base}{separator}u={encoded}")
+        .replace('&', "&amp;")
+        .replace('"', "&quot;");
+    let (start, end) = if value_end < next {
+        (value_start - 1, next)
+    } else {
+        (value_start, value_end)
+    };
+    format!(
+        "{}\"{wrapped}\"{}",
+        &tag[..start],
+        &tag[end.min(tag.len())..]
+    )
+}
+
+/// Whether an `href` points somewhere on the web, as opposed to an anchor or a
+/// `mailto:`/`tel:` link.
+fn is_checkable_link(href: &str) -> bool {
+    let trimmed = href.trim().to_ascii_lowercase();
+    !(trimmed.is_empty()
+        || trimmed.starts_with('#')
+        || trimmed.starts_with("mailto:")
+        || trimmed.starts_with("tel:"))
+}
+
+/// Locates the `href` value on an `<a>` or `<area>` tag, returning the value's
+/// start and end offsets and the offset just past it (including any quote).
+fn find_tag_href(tag: &str) -> Option<(usize, usize, usize)> {
+    let name_end = tag[1..]
+        .find(|ch: char| !ch.is_ascii_alphanumeric())
+        .map_or(tag.len(), |index| index + 1);
+    let name = &tag[1..name_end];
+    if !name.eq_ignore_ascii_case("a") && !name.eq_ignore_ascii_case("area") {
+        return None;
+    }
+
+    let bytes = tag.as_bytes();
+    let mut pos = name_end;
+    while pos < bytes.len() {
+        while pos < bytes.len() && (bytes[pos].is_ascii_whitespace() || bytes[pos] == b'/') {
+            pos += 1;
+        }
+        let attr_start = pos;
+        while pos < bytes.len()
+            && !matches!(bytes[pos], b'=' | b'>' | b'/')
+            && !bytes[pos].is_ascii_whitespace()
+        {
+            pos += 1;
+        }
+        let attr = &tag[attr_start..pos];
+        while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if attr.is_empty() || bytes.get(pos) != Some(&b'=') {
+            if attr.is_empty() {
+                pos += 1;
+            }
+            continue;
+        }
+        pos += 1;
+        while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        let (value_start, value_end, next) = match bytes.get(pos) {
+            Some(&quote @ (b'"' | b'\'')) => {
+                let close = tag[pos + 1..]
+                    .find(quote as char)
+                    .map_or(tag.len(), |index| pos + 1 + index);
+                (pos + 1, close, close + 1)
+            }
+            _ => {
+                let end = tag[pos..]
+                    .find(|ch: char| ch.is_ascii_whitespace() || ch == '>')
+                    .map_or(tag.len(), |index| pos + index);
+                (pos, end, end)
+            }
+        };
+        if attr.eq_ignore_ascii_case("href") {
+            return Some((value_start, value_end, next));
+        }
+        pos = next;
+    }
+    None
+}
+
+/// Collects the distinct web links from `<a>`/`<area>` tags in `html`, skipping
+/// anchors, `mailto:`/`tel:` links and unresolved `{{placeholders}}`.
+fn extract_links(html: &str) -> Vec<String> {
+    let mut links: Vec<String> = Vec::new();
+    map_tags(html, |tag| {
+        if let Some((start, end, _)) = find_tag_href(tag) {
+            let href = tag[start..end].replace("&amp;", "&").trim().to_string();
+            if is_checkable_link(&href) && !href.contains("{{") && !links.contains(&href) {
+                links.push(href);
+            }
+        }
+        tag.to_string()
+    });
+    links
+}
+
+/// Sends a HEAD request (falling back to GET when HEAD is refused) to each link in
+/// the rendered HTML template, before --link-wrap and --tracking-pixel add their
+/// own URLs. Broken links are warnings unless `--check-links-strict` is set.
+/// Meant as a preflight that runs once, before anything is sent.
+pub(crate) fn check_links(args: &Args, vars: &TemplateVars, sources: &BodySource) -> Result<()> {
+    if !(args.check_links || args.check_links_strict) {
+        return Ok(());
+    }
+    let Some(html) = &sources.html else {
+        return Ok(());
+    };
+    let links = extract_links(&render_template(html, vars, args)?);
+    log_verbose(
+        args.verbose,
+        &format!("Checking {} link(s) in the HTML body", links.len()),
+    );
+    let mut broken = Vec::new();
+    for link in &links {
+        let status = http_request("HEAD", link, None, "", "link").and_then(|status| {
+            if matches!(status, 405 | 501) {
+                http_request("GET", link, None, "", "link")
+            } else {
+                Ok(status)
+            }
+        });
+        let problem = match status {
+            Ok(status) if (200..400).contains(&status) => {
+                log_verbose(args.verbose, &format!("Link {link} returned HTTP {status}"));
+                continue;
+            }
+            Ok(status) => format!("HTTP {status}"),
+            Err(err) => format!("{err:#}"),
+        };
+        log_warning(&format!("broken link {link}: {problem}"))?;
+        broken.push(link.as_str());
+    }
+    if args.check_links_strict && !broken.is_empty() {
+        return Err(anyhow!(
+            "{} broken link(s) in the HTML body: {}",
+            broken.len(),
+            broken.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Inserts `block` just before `</body>`, or at the end when the document has no
+/// body tag.
+fn insert_before_body_end(html: &str, block: &str) -> String {
+    match html.to_ascii_lowercase().rfind("</body>") {
+        Some(index) => format!("{}{block}{}", &html[..index], &html[index..]),
+        None => format!("{html}\n{block}"),
+    }
+}
+
+/// Subject prefix added by `--alert` unless `--subject-prefix` is given.
+const ALERT_SUBJECT_PREFIX: &str = "[ALERT]";
+
+/// Returns the text of the first `<title>` element with whitespace collapsed
+/// and character references decoded.
+fn html_title(html: &str) -> Option<String> {
+    let lower = html.to_ascii_lowercase();
+    let open = lower.find("<title")?;
+    let start = open + tag_end(&html[open..])?;
+    let end = start + lower[start..].find("</title")?;
+    let title = decode_html_entities(&html[start..end]);
+    let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!title.is_empty()).then_some(title)
+}
+
+/// Decodes numeric character references and the common named ones, leaving
+/// anything unrecognised as written.
+fn decode_html_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find('&') {
+        out.push_str(&rest[..index]);
+        rest = &rest[index..];
+        let decoded = rest.find(';').and_then(|end| {
+            let name = &rest[1..end];
+            let ch = match name {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some('\u{a0}'),
+                _ => match name.strip_prefix('#') {
+                    Some(hex) if hex.starts_with(['x', 'X']) => u32::from_str_radix(&hex[1..], 16)
+                        .ok()
+                        .and_then(char::from_u32),
+                    Some(decimal) => decimal.parse().ok().and_then(char::from_u32),
+                    None => None,
+                },
+            };
+            ch.map(|ch| (ch, end + 1))
+        });
+        match decoded {
+            Some((ch, len)) => {
+                out.push(ch);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Inserts `block` right after the `<body>` tag, or at the start when the
+/// document has no body tag.
+fn insert_after_body_start(html: &str, block: &str) -> String {
+    let start = html.to_ascii_lowercase().find("<body");
+    match start.and_then(|start| tag_end(&html[start..]).map(|end| start + end)) {
+        Some(index) => format!("{}\n{block}{}", &html[..index], &html[index..]),
+        None => format!("{block}{html}"),
+    }
+}
+
+/// Filler after the preheader text so mail clients do not pull the first lines
+/// of the visible body into the inbox preview.
+const PREHEADER_PADDING: &str = "&#8203;&nbsp;";
+
+/// Builds the hidden `<div>` carrying the `--preheader` preview text.
+fn preheader_block(text: &str) -> String {
+    let escaped = text
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    format!(
+        "<div style=\"display:none;font-size:1px;line-height:1px;max-height:0;max-width:0;opacity:0;overflow:hidden;mso-hide:all\">{escaped}{}</div>\n",
+        PREHEADER_PADDING.repeat(100)
+    )
+}
+
+/// Adds the signature as a `<div class="signature">` just before `</body>`, or at
+/// the end when the document has no body tag.
+fn append_html_signature(html: &str, signature: &str) -> String {
+    let escaped = signature
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\n', "<br>\n");
+    let block = format!("<div class=\"signature\">-- <br>\n{escaped}</div>\n");
+    insert_before_body_end(html, &block)
+}
+
+struct CssRule {
+    selector: SimpleSelector,
+    declarations: String,
+    specificity: (usize, usize, usize),
+}
+
+/// A compound selector such as `td`, `.note`, `#header`, or `p.lead`.
+struct SimpleSelector {
+    tag: Option<String>,
+    id: Option<String>,
+    classes: Vec<String>,
+}
+
+impl SimpleSelector {
+    fn parse(selector: &str) -> Option<SimpleSelector> {
+        let re = Regex::new(r"^([a-zA-Z][a-zA-Z0-9]*)?((?:[.#][A-Za-z0-9_-]+)*)$")
+            .expect("valid selector regex");
+        let caps = re.captures(selector.trim())?;
+        let mut parsed = SimpleSelector {
+            tag: caps.get(1).map(|tag| tag.as_str().to_ascii_lowercase()),
+            id: None,
+            classes: Vec::new(),
+        };
+        let part_re = Regex::new(r"([.#])([A-Za-z0-9_-]+)").expect("valid selector part regex");
+        for part in part_re.captures_iter(&caps[2]) {
+            if &part[1] == "#" {
+                parsed.id = Some(part[2].to_string());
+            } else {
+                parsed.classes.push(part[2].to_string());
+            }
+        }
+        if parsed.tag.is_none() && parsed.id.is_none() && parsed.classes.is_empty() {
+            return None;
+        }
+        Some(parsed)
+    }
+
+    fn matches(&self, tag: &str, id: Option<&str>, classes: &[&str]) -> bool {
+        self.tag.as_deref().is_none_or(|wanted| wanted == tag)
+            && self.id.as_deref().is_none_or(|wanted| Some(wanted) == id)
+            && self
+                .classes
+                .iter()
+                .all(|class| classes.contains(&class.as_str()))
+    }
+}
+
+/// Inlines rules with simple selectors. At-rules such as `@media` and rules
+/// using combinators or pseudo-classes cannot be inlined, so they stay in a
+/// single retained `<style>` block.
+fn inline_css(html: &str) -> String {
+    let style_re = Regex::new(r"(?is)<style[^>]*>(.*?)</style>").expect("valid style regex");
+    if !style_re.is_match(html) {
+        return html.to_string();
+    }
+
+    let mut rules = Vec::new();
+    let mut retained = String::new();
+    for caps in style_re.captures_iter(html) {
+        parse_stylesheet(&caps[1], &mut rules, &mut retained);
+    }
+    rules.sort_by_key(|rule| rule.specificity);
+
+    let mut first = true;
+    let html = style_re.replace_all(html, |_: &regex::Captures| {
+        if std::mem::take(&mut first) && !retained.is_empty() {
+            format!("<style>{}</style>", retained.trim_end())
+        } else {
+            String::new()
+        }
+    });
+
+    let tag_re = Regex::new(r"<([a-zA-Z][a-zA-Z0-9]*)(\s[^<>]*)?>").expect("valid tag regex");
+    let attr_re = Regex::new(r#"(?i)\b(class|id|style)\s*=\s*(?:"([^"]*)"|'([^']*)')"#)
+        .expect("valid attribute regex");
+    tag_re
+        .replace_all(&html, |caps: &regex::Captures| {
+            let tag = caps[1].to_ascii_lowercase();
+            let attrs = caps.get(2).map_or("", |attrs| attrs.as_str());
+            let (mut id, mut class, mut inline) = (None, "", "");
+            for attr in attr_re.captures_iter(attrs) {
+                let value = attr
+                    .get(2)
+                    .or(attr.get(3))
+                    .map_or("", |value| value.as_str());
+                match attr[1].to_ascii_lowercase().as_str() {
+                    "id" => id = Some(value),
+                    "class" => class = value,
+                    _ => inline = value,
+                }
+            }
+            let classes = class.split_whitespace().collect::<Vec<_>>();
+            let mut style = rules
+                .iter()
+                .filter(|rule| rule.selector.matches(&tag, id, &classes))
+                .map(|rule| rule.declarations.as_str())
+                .collect::<Vec<_>>();
+            if style.is_empty() {
+                return caps[0].to_string();
+            }
+            // Existing inline declarations come last so they keep winning.
+            if !inline.trim().is_empty() {
+                style.push(inline.trim().trim_end_matches(';'));
+            }
+
+            let attrs = attr_re.replace_all(attrs, |attr: &regex::Captures| {
+                if attr[1].eq_ignore_ascii_case("style") {
+                    String::new()
+                } else {
+                    attr[0].to_string()
+                }
+            });
+            let (attrs, closing) = match attrs.trim_end().strip_suffix('/') {
+                Some(attrs) => (attrs.trim_end().to_string(), " /"),
+                None => (attrs.trim_end().to_string(), ""),
+            };
+            format!(
+                "<{}{attrs} style=\"{}\"{closing}>",
+                &caps[1],
+                style.join("; ").replace('"', "'")
+            )
+        })
+        .into_owned()
+}
+
+fn parse_stylesheet(css: &str, rules: &mut Vec<CssRule>, retained: &mut String) {
+    let comment_re = Regex::new(r"(?s)/\*.*?\*/").expect("valid comment regex");
+    let css = comment_re.replace_all(css, "");
+    let mut depth = 0;
+    let mut start = 0;
+    for (index, ch) in css.char_indices() {
+        match ch {
+            '{' => depth += 1,
+            '}' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    let block = css[start..=index].trim();
+                    start = index + 1;
+                    collect_rule(block, rules, retained);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn collect_rule(block: &str, rules: &mut Vec<CssRule>, retained: &mut String) {
+    let Some((selectors, body)) = block.split_once('{') else {
+        return;
+    };
+    let declarations = body
+        .trim_end_matches('}')
+        .split(';')
+        .map(str::trim)
+        .filter(|declaration| !declaration.is_empty())
+        .collect::<Vec<_>>()
+        .join("; ");
+    if selectors.trim_start().starts_with('@') {
+        retained.push_str(block);
+        retained.push('\n');
+        return;
+    }
+
+    let mut unsupported = Vec::new();
+    for selector in selectors.split(',') {
+        match SimpleSelector::parse(selector) {
+            Some(parsed) => {
+                let specificity = (
+                    usize::from(parsed.id.is_some()),
+                    parsed.classes.len(),
+                    usize::from(parsed.tag.is_some()),
+                );
+                rules.push(CssRule {
+                    selector: parsed,
+                    declarations: declarations.clone(),
+                    specificity,
+                });
+            }
+            None => unsupported.push(selector.trim()),
+        }
+    }
+    if !unsupported.is_empty() {
+        retained.push_str(&format!(
+            "{} {{ {declarations} }}\n",
+            unsupported.join(", ")
+        ));
+    }
+}
+
+/// Minifies HTML conservatively: `<pre>`, `<textarea>` and `<script>` blocks are
+/// copied verbatim, and Outlook conditional comments are kept.
+fn minify_html(html: &str) -> String {
+    let verbatim_re = Regex::new(
+        r"(?is)<pre\b.*?</pre\s*>|<textarea\b.*?</textarea\s*>|<script\b.*?</script\s*>",
+    )
+    .expect("valid verbatim regex");
+    let comment_re = Regex::new(r"(?s)<!--(.*?)-->").expect("valid comment regex");
+    let space_re = Regex::new(r"\s+").expect("valid whitespace regex");
+    let tag_re = Regex::new(r"<[a-zA-Z][^<>]*>").expect("valid tag regex");
+    let redundant_re = Regex::new(
+        r#"(?i)\s+(?:type\s*=\s*(?:"text/(?:css|javascript)"|'text/(?:css|javascript)')|(?:class|id|style)\s*=\s*(?:""|''))"#,
+    )
+    .expect("valid attribute regex");
+
+    let minify = |segment: &str| {
+        let segment = comment_re.replace_all(segment, |caps: &regex::Captures| {
+            if caps[1].starts_with('[') || caps[1].starts_with("<![") {
+                caps[0].to_string()
+            } else {
+                String::new()
+            }
+        });
+        let segment = space_re.replace_all(&segment, " ");
+        tag_re
+            .replace_all(&segment, |caps: &regex::Captures| {
+                redundant_re.replace_all(&caps[0], "").into_owned()
+            })
+            .into_owned()
+    };
+
+    let mut out = String::with_capacity(html.len());
+    let mut last = 0;
+    for verbatim in verbatim_re.find_iter(html) {
+        out.push_str(&minify(&html[last..verbatim.start()]));
+        out.push_str(verbatim.as_str());
+        last = verbatim.end();
+    }
+    out.push_str(&minify(&html[last..]));
+    out.trim().to_string()
+}
+
+/// Renders the sender and recipient addresses.
+pub fn render_addresses(args: &Args, vars: &TemplateVars, from: String) -> Result<Addresses> {
+    let render_all = |values: &[String]| {
+        values
+            .iter()
+            .map(|value| render_template(value, vars, args))
+            .collect::<Result<Vec<_>>>()
+    };
+    Ok(Addresses {
+        from: render_template(&from, vars, args)?,
+        to: render_all(&args.to)?,
+        cc: render_all(&args.cc)?,
+        bcc: render_all(&args.bcc)?,
+        envelope_to: render_all(&args.envelope_to)?,
+    })
+}
+
+/// Loads the text and HTML body templates from flags or files.
+///
+/// With `--crlf` the bodies are converted to CRLF up front. lettre already
+/// emits CRLF when it encodes text parts, so the DKIM body hash is the same
+/// either way; the flag makes the rendered bodies and the --max-size breakdown
+/// match what is sent.
+pub fn load_body_sources(args: &Args, vars: &TemplateVars) -> Result<BodySource> {
+    let (lang, html) = if args.html_file_lang.is_empty() {
+        (
+            None,
+            resolve_body_source("html", &args.html, &args.html_file)?,
+        )
+    } else {
+        let (lang, html) = load_localized_html(args, vars)?;
+        (Some(lang), Some(html))
+    };
+    let line_endings = |body: Option<String>| match body {
+        Some(body) if args.crlf => Some(to_crlf(&body)),
+        body => body,
+    };
+    let sources = BodySource {
+        text: line_endings(resolve_body_source("text", &args.text, &args.text_file)?),
+        html: line_endings(html),
+        amp_html: line_endings(resolve_body_source(
+            "amp-html",
+            &args.amp_html,
+            &args.amp_html_file,
+        )?),
+        signature: args
+            .signature_file
+            .as_ref()
+            .map(|path| {
+                fs::read_to_string(path)
+                    .with_context(|| format!("failed to read signature from {}", path.display()))
+            })
+            .transpose()?,
+        headers: match &args.header_file {
+            Some(path) => load_header_file(path)?,
+            None => Vec::new(),
+        },
+        lang,
+    };
+    if signature_is_dropped(args, &sources) {
+        log_warning(
+            "--signature-file is only added to the text body and there is none; \
+             pass --signature-html to add it to the HTML body",
+        )?;
+    }
+    Ok(sources)
+}
+
+/// Whether the `--signature-file` has no body to go into: it goes after the
+/// text body, and into the HTML body only with `--signature-html`.
+fn signature_is_dropped(args: &Args, sources: &BodySource) -> bool {
+    sources.signature.is_some()
+        && sources.text.is_none()
+        && !(args.signature_html && sources.html.is_some())
+}
+
+/// Converts bare `\n` to `\r\n`, leaving existing CRLF pairs alone.
+fn to_crlf(body: &str) -> String {
+    body.replace("\r\n", "\n").replace('\n', "\r\n")
+}
+
+pub(crate) fn load_header_file(path: &Path) -> Result<Vec<String>> {
+    let data = fs::read_to_string(path)
+        .with_context(|| format!("failed to read header file {}", path.display()))?;
+    let mut headers: Vec<String> = Vec::new();
+    for line in data.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if line.starts_with([' ', '\t']) {
+            let last = headers.last_mut().ok_or_else(|| {
+                anyhow!(
+                    "header file {} starts with a continuation line",
+                    path.display()
+                )
+            })?;
+            last.push(' ');
+            last.push_str(line.trim());
+        } else {
+            headers.push(line.to_string());
+        }
+    }
+    Ok(headers)
+}
+
+/// Accepts a comma-separated list of BCP 47 style tags: a 1-8 letter primary
+/// subtag followed by 1-8 character alphanumeric subtags, e.g. `fr-CA`, `zh-Hant-TW`.
+pub(crate) fn validate_language_tags(value: &str) -> Result<()> {
+    for tag in value.split(',').map(str::trim) {
+        let mut subtags = tag.split('-');
+        let primary = subtags.next().unwrap_or_default();
+        let valid = (1..=8).contains(&primary.len())
+            && primary.chars().all(|ch| ch.is_ascii_alphabetic())
+            && subtags.all(|subtag| {
+                (1..=8).contains(&subtag.len())
+                    && subtag.chars().all(|ch| ch.is_ascii_alphanumeric())
+            });
+        if !valid {
+            return Err(anyhow!("invalid --content-language tag: {tag}"));
+        }
+    }
+    Ok(())
+}
+
+/// Picks the `--html-file-lang` body for the requested language, returning the
+/// language it was found under along with its contents.
+fn load_localized_html(args: &Args, vars: &TemplateVars) -> Result<(String, String)> {
+    let files = args
+        .html_file_lang
+        .iter()
+        .map(|entry| {
+            let (lang, path) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("--html-file-lang expects lang=path, got: {entry}"))?;
+            Ok((lang.trim(), Path::new(path.trim())))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let find = |lang: &str| {
+        files
+            .iter()
+            .find(|(candidate, _)| candidate.eq_ignore_ascii_case(lang.trim()))
+    };
+
+    let requested = match &args.lang {
+        Some(lang) => Some(render_template(lang, vars, args)?),
+        None => vars.get("__lang").cloned(),
+    };
+    let default = args.default_lang.as_deref().unwrap_or(files[0].0);
+    let (lang, path) = match requested.as_deref().and_then(find) {
+        Some(entry) => entry,
+        None => {
+            if let Some(requested) = &requested {
+                log_verbose(
+                    args.verbose,
+                    &format!("No HTML body for language {requested}, falling back to {default}"),
+                );
+            }
+            find(default).ok_or_else(|| {
+                anyhow!("--default-lang {default} has no matching --html-file-lang")
+            })?
+        }
+    };
+    log_verbose(
+        args.verbose,
+        &format!("Using {lang} HTML body from {}", path.display()),
+    );
+    let html = fs::read_to_string(path)
+        .with_context(|| format!("failed to read html body from {}", path.display()))?;
+    Ok((lang.to_string(), html))
+}
+
+fn resolve_body_source(
+    label: &str,
+    inline: &Option<String>,
+    file: &Option<PathBuf>,
+) -> Result<Option<String>> {
+    match (inline, file) {
+        (Some(_), Some(_)) => Err(anyhow!(
+            "provide either --{label} or --{label}-file, not both"
+        )),
+        (Some(value), None) => Ok(Some(value.clone())),
+        (None, Some(path)) => {
+            let data = fs::read_to_string(path)
+                .with_context(|| format!("failed to read {label} body from {}", path.display()))?;
+            Ok(Some(data))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn vars(entries: &[&str]) -> TemplateVars {
+        parse_vars(
+            &entries
+                .iter()
+                .map(|entry| entry.to_string())
+                .collect::<Vec<_>>(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_vars() {
+        let vars = vars(&[" name =Ada", "empty=", "eq=a=b"]);
+        assert_eq!(vars["name"], "Ada");
+        assert_eq!(vars["empty"], "");
+        assert_eq!(vars["eq"], "a=b");
+        assert!(parse_vars(&["novalue".to_string()]).is_err());
+        assert!(parse_vars(&[" =x".to_string()]).is_err());
+    }
+
+    #[test]
+    fn applies_templates_leaving_unknown_placeholders() {
+        let vars = vars(&["name=Ada", "user.id=7"]);
+        assert_eq!(
+            apply_template("Hi {{ name }} #{{user.id}} {{missing}}", &vars),
+            "Hi Ada #7 {{missing}}"
+        );
+    }
+
+    #[test]
+    fn strict_vars_reject_unknown_placeholders() {
+        let args = Args::parse_from(["wirepost", "--strict-vars"]);
+        let vars = vars(&["name=Ada"]);
+        assert_eq!(render_template("{{name}}", &vars, &args).unwrap(), "Ada");
+        let error = render_template("{{name}} {{other}}", &vars, &args).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "template variable `other` is not defined"
+        );
+    }
+
+    #[test]
+    fn expands_environment_variables() {
+        let home = env::var("HOME").unwrap_or_default();
+        assert_eq!(
+            expand_env("${HOME}/x ${WIREPOST_TEST_UNSET}", false).unwrap(),
+            format!("{home}/x ${{WIREPOST_TEST_UNSET}}")
+        );
+        assert!(expand_env("${WIREPOST_TEST_UNSET}", true).is_err());
+    }
+
+    #[test]
+    fn variable_values_are_not_expanded_from_the_environment() {
+        let args = Args::parse_from(["wirepost", "--expand-env", "--strict-vars"]);
+        let home = env::var("HOME").unwrap_or_default();
+        let vars = vars(&["name=${HOME}", "missing=${WIREPOST_TEST_UNSET}"]);
+        assert_eq!(
+            render_template("${HOME}: {{name}} {{missing}}", &vars, &args).unwrap(),
+            format!("{home}: ${{HOME}} ${{WIREPOST_TEST_UNSET}}")
+        );
+    }
+
+    #[test]
+    fn wraps_paragraphs_but_not_quotes() {
+        let text = "one two three four five\n\n> quoted line that is long\n";
+        assert_eq!(
+            wrap_text(text, 9),
+            "one two\nthree\nfour five\n\n> quoted line that is long\n"
+        );
+        assert_eq!(
+            wrap_text("see https://example.com/long", 5),
+            "see\nhttps://example.com/long"
+        );
+    }
+
+    #[test]
+    fn finds_hrefs_with_quotes_and_angle_brackets() {
+        let tag = r#"<a title="x > y" href='https://example.com/?a=1&amp;b=2'>"#;
+        let (start, end, _) = find_tag_href(tag).unwrap();
+        assert_eq!(&tag[start..end], "https://example.com/?a=1&amp;b=2");
+        assert_eq!(find_tag_href(r#"<link href="style.css">"#), None);
+        assert_eq!(
+            extract_links(
+                r##"<a href="#top">x</a><a href=mailto:a@b>y</a><a href="https://e.com">z</a><!-- <a href="https://hidden"> -->"##
+            ),
+            ["https://e.com"]
+        );
+    }
+
+    #[test]
+    fn reads_the_html_title() {
+        assert_eq!(
+            html_title("<html><TITLE lang=\"en\">\n  Fish &amp; chips &#x2013; &#8364;5 </TITLE>"),
+            Some("Fish & chips – €5".to_string())
+        );
+        assert_eq!(html_title("<title> </title>"), None);
+        assert_eq!(decode_html_entities("&bogus; &amp"), "&bogus; &amp");
+    }
+
+    #[test]
+    fn inline_css_orders_rules_by_specificity() {
+        let html = "<style>#intro { color: green } p.lead { color: red } .lead { color: black } \
+                    p { color: blue } .lead { margin: 0 }</style><p id=\"intro\" class=\"lead\">x</p>";
+        assert_eq!(
+            inline_css(html),
+            "<p id=\"intro\" class=\"lead\" style=\"color: blue; color: black; margin: 0; \
+             color: red; color: green\">x</p>"
+        );
+    }
+
+    #[test]
+    fn inline_css_keeps_important_declarations_ahead_of_inline_styles() {
+        // An `!important` declaration outranks later ones in the same style
+        // attribute, so it keeps beating both the class rule and the inline style.
+        let html = "<style>p { color: red !important } .note { color: blue }</style>\
+                    <p class=\"note\" style=\"color: green;\">x</p>";
+        assert_eq!(
+            inline_css(html),
+            "<p class=\"note\" style=\"color: red !important; color: blue; color: green\">x</p>"
+        );
+    }
+
+    #[test]
+    fn inline_css_retains_at_rules_and_complex_selectors() {
+        let html = "<style>@media (max-width: 600px) { .note { color: red } } \
+                    @font-face { font-family: Brand; src: url(brand.woff) } \
+                    td a, a:hover { color: blue } .note { font-weight: bold }</style>\
+                    <p class=\"note\">x</p>";
+        assert_eq!(
+            inline_css(html),
+            "<style>@media (max-width: 600px) { .note { color: red } }\n\
+             @font-face { font-family: Brand; src: url(brand.woff) }\n\
+             td a, a:hover { color: blue }</style>\
+             <p class=\"note\" style=\"font-weight: bold\">x</p>"
+        );
+    }
+
+    #[test]
+    fn minify_html_keeps_pre_blocks_verbatim() {
+        let html =
+            "<div>\n    <pre class=\"code\">  fn main() {\n\n      run();\n  }  </pre>\n</div>\n";
+        assert_eq!(
+            minify_html(html),
+            "<div> <pre class=\"code\">  fn main() {\n\n      run();\n  }  </pre> </div>"
+        );
+    }
+
+    #[test]
+    fn minify_html_keeps_textarea_contents_verbatim() {
+        let html = "<form>\n  <textarea name=\"reply\">\n  Dear team,\n\n  <!-- not a comment -->\n</textarea>\n  \
+                    <!-- footer --> <p class=\"\">y</p>\n</form>";
+        assert_eq!(
+            minify_html(html),
+            "<form> <textarea name=\"reply\">\n  Dear team,\n\n  <!-- not a comment -->\n</textarea> \
+             <p>y</p> </form>"
+        );
+    }
+
+    #[test]
+    fn minify_html_keeps_conditional_comments() {
+        let html = "<!--[if mso]>\n  <table><tr><td>\n<![endif]-->\n\
+                    <!--[if !mso]><!-->\n  <div>x</div>\n<!--<![endif]-->\n\
+                    <!-- build 42 -->\n<p>y</p>";
+        assert_eq!(
+            minify_html(html),
+            "<!--[if mso]> <table><tr><td> <![endif]--> <!--[if !mso]><!--> <div>x</div> \
+             <!--<![endif]--> <p>y</p>"
+        );
+    }
+
+    #[test]
+    fn flags_a_signature_without_a_body_to_go_into() {
+        let sources = |text: Option<&str>| BodySource {
+            text: text.map(str::to_string),
+            html: Some("<p>hi</p>".into()),
+            amp_html: None,
+            signature: Some("Jane".into()),
+            headers: Vec::new(),
+            lang: None,
+        };
+        let args = |extra: &[&str]| {
+            Args::parse_from(
+                ["wirepost", "--signature-file", "sig.txt"]
+                    .iter()
+                    .chain(extra),
+            )
+        };
+        assert!(signature_is_dropped(&args(&[]), &sources(None)));
+        assert!(!signature_is_dropped(
+            &args(&["--signature-html"]),
+            &sources(None)
+        ));
+        assert!(!signature_is_dropped(&args(&[]), &sources(Some("hi"))));
+    }
+}